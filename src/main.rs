@@ -1,22 +1,38 @@
+use std::collections::HashMap;
 use std::f32::consts::TAU;
 
 use ggez::conf::WindowMode;
 use ggez::glam::{Vec2, vec2};
 use rand::Rng;
 use ggez::{Context, ContextBuilder, GameResult};
-use ggez::graphics::{self, Color, DrawMode, DrawParam, InstanceArray, Mesh, MeshBuilder, StrokeOptions};
-use ggez::event::{self, EventHandler};
+use ggez::graphics::{self, Color, DrawMode, DrawParam, InstanceArray, Mesh, MeshBuilder, Rect, StrokeOptions};
+use ggez::event::{self, EventHandler, MouseButton};
+use ggez::input::keyboard::{KeyCode, KeyInput};
 
 const SIMULATION_WIDTH: i32 = 800;
 const SIMULATION_HEIGHT: i32 = 600;
 const MARGIN: f32 = 128.0;
 
 const BOID_COUNT: i32 = 350;
+const PREDATOR_COUNT: i32 = 2;
 
 const BOID_BODY_RADIUS_SCALE: f32 = 1.0;
 const BOID_FACE_LENGTH_SCALE: f32 = 1.0;
+const PREDATOR_BODY_RADIUS_SCALE: f32 = 2.5;
+const PREDATOR_FACE_LENGTH_SCALE: f32 = 2.5;
+const PREDATOR_COLOR: Color = Color::new(1.0, 0.2, 0.2, 1.0);
 
 const BOID_START_ACCEL: f32 = 10.0;
+const BOID_DELETE_RADIUS: f32 = 20.0;
+
+const DEBUG_VELOCITY_LINE_SCALE: f32 = 0.5;
+const DEBUG_VISUAL_RANGE_COLOR: Color = Color::new(1.0, 1.0, 1.0, 0.2);
+const DEBUG_PROTECTED_RANGE_COLOR: Color = Color::new(1.0, 0.3, 0.3, 0.3);
+const DEBUG_VELOCITY_COLOR: Color = Color::new(0.3, 1.0, 0.3, 0.6);
+const DEBUG_NEIGHBOUR_LINK_COLOR: Color = Color::new(0.3, 0.6, 1.0, 0.3);
+
+const SIMULATION_HZ: f32 = 60.0;
+const SIMULATION_DT: f32 = 1.0 / SIMULATION_HZ;
 
 fn main() {
     let (mut context, event_loop) = ContextBuilder::new("boids", "Tachytaenius")
@@ -27,8 +43,38 @@ fn main() {
     event::run(context, event_loop, boids);
 }
 
+// Wraps a heading in radians, normalized to [0, TAU), centralizing conversion to and from Vec2
+// so it isn't duplicated as raw trig wherever a direction needs to become an angle or vice versa
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Angle(f32);
+
+impl Angle {
+    fn new(radians: f32) -> Angle {
+        Angle(radians.rem_euclid(TAU))
+    }
+
+    fn from_vec(vector: Vec2) -> Angle {
+        Angle::new(vector.y.atan2(vector.x))
+    }
+
+    fn to_vec(self) -> Vec2 {
+        Vec2::from_angle(self.0)
+    }
+
+    fn random(rng: &mut impl Rng) -> Angle {
+        Angle::new(rng.gen::<f32>() * TAU)
+    }
+}
+
+impl From<Angle> for f32 {
+    fn from(angle: Angle) -> f32 {
+        angle.0
+    }
+}
+
 struct Boid {
     position: Vec2,
+    previous_position: Vec2,
     velocity: Vec2,
 
     scale: f32,
@@ -39,13 +85,47 @@ struct Boid {
     avoidance_factor: f32,
     matching_factor: f32,
     cohesion_factor: f32,
-    back_to_bounds_factor: f32
+    back_to_bounds_factor: f32,
+    obstacle_avoidance_range: f32,
+    obstacle_avoidance_factor: f32,
+    flee_factor: f32
+}
+
+// An axis-aligned box obstacle that boids steer away from and cannot pass through
+struct Obstacle {
+    center: Vec2,
+    half_extents: Vec2
+}
+
+impl Obstacle {
+    fn closest_point(&self, position: Vec2) -> Vec2 {
+        position.clamp(self.center - self.half_extents, self.center + self.half_extents)
+    }
+}
+
+// A predator agent that hunts the centroid of nearby boids
+struct Predator {
+    position: Vec2,
+    previous_position: Vec2,
+    velocity: Vec2,
+
+    scale: f32,
+    max_speed: f32,
+    detection_range: f32,
+    seek_factor: f32
 }
 
 struct Boids {
     boids: Vec<Boid>,
     boid_mesh: Mesh,
-    boid_instance_array: InstanceArray
+    boid_instance_array: InstanceArray,
+    obstacles: Vec<Obstacle>,
+    obstacle_mesh: Mesh,
+    predators: Vec<Predator>,
+    predator_mesh: Mesh,
+    predator_instance_array: InstanceArray,
+    accumulator: f32,
+    debug: bool
 }
 
 impl Boids {
@@ -56,83 +136,184 @@ impl Boids {
             vec2(BOID_BODY_RADIUS_SCALE, 0.0),
             vec2(BOID_BODY_RADIUS_SCALE + BOID_FACE_LENGTH_SCALE, 0.0)
         ], 1.0, Color::WHITE).unwrap();
+
+        let obstacles = vec![
+            Obstacle { center: vec2(SIMULATION_WIDTH as f32 * 0.5, SIMULATION_HEIGHT as f32 * 0.5), half_extents: vec2(50.0, 50.0) },
+            Obstacle { center: vec2(SIMULATION_WIDTH as f32 * 0.25, SIMULATION_HEIGHT as f32 * 0.7), half_extents: vec2(70.0, 30.0) },
+            Obstacle { center: vec2(SIMULATION_WIDTH as f32 * 0.75, SIMULATION_HEIGHT as f32 * 0.3), half_extents: vec2(30.0, 70.0) }
+        ];
+        let mut obstacle_mesh_builder = MeshBuilder::new();
+        for obstacle in &obstacles {
+            let top_left = obstacle.center - obstacle.half_extents;
+            let size = obstacle.half_extents * 2.0;
+            obstacle_mesh_builder.rectangle(
+                DrawMode::Stroke(StrokeOptions::default()),
+                Rect::new(top_left.x, top_left.y, size.x, size.y),
+                Color::WHITE
+            ).unwrap();
+        }
+
+        let mut predator_mesh_builder = MeshBuilder::new();
+        predator_mesh_builder.circle(DrawMode::Stroke(StrokeOptions::default()), Vec2::ZERO, PREDATOR_BODY_RADIUS_SCALE, 0.1, PREDATOR_COLOR).unwrap();
+        predator_mesh_builder.line(&[
+            vec2(PREDATOR_BODY_RADIUS_SCALE, 0.0),
+            vec2(PREDATOR_BODY_RADIUS_SCALE + PREDATOR_FACE_LENGTH_SCALE, 0.0)
+        ], 1.0, PREDATOR_COLOR).unwrap();
+
         let mut state = Boids {
             boids: Vec::<Boid>::new(),
             boid_mesh: Mesh::from_data(context, boid_mesh_builder.build()),
-            boid_instance_array: InstanceArray::new(context, None)
+            boid_instance_array: InstanceArray::new(context, None),
+            obstacles,
+            obstacle_mesh: Mesh::from_data(context, obstacle_mesh_builder.build()),
+            predators: Vec::<Predator>::new(),
+            predator_mesh: Mesh::from_data(context, predator_mesh_builder.build()),
+            predator_instance_array: InstanceArray::new(context, None),
+            accumulator: 0.0,
+            debug: false
         };
         let mut rng = rand::thread_rng();
         for _ in 0..BOID_COUNT {
-            let angle = rng.gen::<f32>() * TAU;
-            state.boids.push(Boid {
-                position: vec2(rng.gen::<f32>() * SIMULATION_WIDTH as f32, rng.gen::<f32>() * SIMULATION_HEIGHT as f32),
-                velocity: Vec2::from_angle(angle) * rng.gen::<f32>().sqrt() * 50.0,
-
-                scale: 2.0,
-                min_speed: 10.0,
-                max_speed: 100.0,
-                protected_range: 20.0,
-                visual_range: 100.0,
-                avoidance_factor: 0.75,
-                matching_factor: 1.0,
-                cohesion_factor: 0.5,
-                back_to_bounds_factor: 150.0
-            });
+            let position = vec2(rng.gen::<f32>() * SIMULATION_WIDTH as f32, rng.gen::<f32>() * SIMULATION_HEIGHT as f32);
+            state.boids.push(Self::new_boid(position, &mut rng));
+        }
+        for _ in 0..PREDATOR_COUNT {
+            let position = vec2(rng.gen::<f32>() * SIMULATION_WIDTH as f32, rng.gen::<f32>() * SIMULATION_HEIGHT as f32);
+            state.predators.push(Self::new_predator(position, &mut rng));
         }
 
         state
     }
-}
 
-impl EventHandler for Boids {
-    fn update(&mut self, context: &mut Context) -> GameResult {
-        let dt = context.time.delta().as_secs_f32();
+    // Builds a boid at the given position with a small random velocity, as used both for the
+    // initial flock and for boids spawned interactively
+    fn new_boid(position: Vec2, rng: &mut impl Rng) -> Boid {
+        let angle = Angle::random(rng);
+        Boid {
+            position,
+            previous_position: position,
+            velocity: angle.to_vec() * rng.gen::<f32>().sqrt() * 50.0,
+
+            scale: 2.0,
+            min_speed: 10.0,
+            max_speed: 100.0,
+            protected_range: 20.0,
+            visual_range: 100.0,
+            avoidance_factor: 0.75,
+            matching_factor: 1.0,
+            cohesion_factor: 0.5,
+            back_to_bounds_factor: 150.0,
+            obstacle_avoidance_range: 40.0,
+            obstacle_avoidance_factor: 200.0,
+            flee_factor: 300.0
+        }
+    }
+
+    // Builds a predator at the given position with a small random velocity
+    fn new_predator(position: Vec2, rng: &mut impl Rng) -> Predator {
+        let angle = Angle::random(rng);
+        Predator {
+            position,
+            previous_position: position,
+            velocity: angle.to_vec() * rng.gen::<f32>().sqrt() * 50.0,
+
+            scale: 2.0,
+            max_speed: 120.0,
+            detection_range: 150.0,
+            seek_factor: 80.0
+        }
+    }
+
+    // Buckets boid indices into a uniform grid of the given cell size, keyed by cell coordinate
+    fn build_grid(&self, cell_size: f32) -> HashMap<(i32, i32), Vec<usize>> {
+        let mut grid = HashMap::<(i32, i32), Vec<usize>>::new();
+        for (i, boid) in self.boids.iter().enumerate() {
+            grid.entry(Self::cell_of(boid.position, cell_size)).or_default().push(i);
+        }
+        grid
+    }
+
+    fn cell_of(position: Vec2, cell_size: f32) -> (i32, i32) {
+        ((position.x / cell_size).floor() as i32, (position.y / cell_size).floor() as i32)
+    }
+
+    // Advances the flock by one fixed simulation step
+    fn step(&mut self, dt: f32) {
         let mut rng = rand::thread_rng();
 
+        // Cell size is the largest visual range so that a boid's 3x3 neighbourhood always covers it
+        let cell_size = self.boids.iter().map(|boid| boid.visual_range).fold(f32::MIN_POSITIVE, f32::max);
+        let grid = self.build_grid(cell_size);
+
+        // Snapshot positions/velocities so the write pass below can mutate self.boids by index
+        // without aliasing the data the neighbour scan reads
+        let positions: Vec<Vec2> = self.boids.iter().map(|boid| boid.position).collect();
+        let velocities: Vec<Vec2> = self.boids.iter().map(|boid| boid.velocity).collect();
+
         for i in 0..self.boids.len() {
-            // Split up the vector into boids below the current boid, the current boid, and boids above the current boid
-            let (boids_low, boids_high) = self.boids.split_at_mut(i);
-            let (boid, boids_high) = boids_high.split_first_mut().unwrap();
+            let boid = &mut self.boids[i];
+            let (cell_x, cell_y) = Self::cell_of(positions[i], cell_size);
 
             // React to other boids
             let mut close = Vec2::ZERO;
             let mut neighbour_position_sum = Vec2::ZERO;
             let mut neighbour_velocity_sum = Vec2::ZERO;
             let mut neighbours = 0;
-            let mut process_other_boid = |other_boid: &Boid| {
-                let other_to_boid = boid.position - other_boid.position;
-                if other_to_boid.length() <= boid.visual_range {
-                    neighbour_position_sum += other_boid.position;
-                    neighbour_velocity_sum += other_boid.velocity;
-                    neighbours += 1;
-                }
-                if other_to_boid.length() <= boid.protected_range {
-                    close += other_to_boid;
+            for x in cell_x - 1..=cell_x + 1 {
+                for y in cell_y - 1..=cell_y + 1 {
+                    let Some(cell) = grid.get(&(x, y)) else { continue };
+                    for &j in cell {
+                        if j == i {
+                            continue;
+                        }
+                        let other_to_boid = positions[i] - positions[j];
+                        if other_to_boid.length() <= boid.visual_range {
+                            neighbour_position_sum += positions[j];
+                            neighbour_velocity_sum += velocities[j];
+                            neighbours += 1;
+                        }
+                        if other_to_boid.length() <= boid.protected_range {
+                            close += other_to_boid;
+                        }
+                    }
                 }
-            };
-            for other_boid in boids_low.iter() {
-                process_other_boid(other_boid);
-            }
-            for other_boid in boids_high.iter() {
-                process_other_boid(other_boid);
             }
             boid.velocity += close * boid.avoidance_factor * dt;
             if neighbours > 0 {
                 boid.velocity += (neighbour_position_sum / neighbours as f32 - boid.position) * boid.cohesion_factor * dt;
                 boid.velocity += neighbour_velocity_sum / neighbours as f32 * boid.matching_factor * dt;
             }
-            
-            // Enforce min and max speed
-            if dt > 0.0 { // If dt is zero, this will break
-                // Give a random direction to velocity if speed is zero
-                if boid.velocity == Vec2::ZERO {
-                    boid.velocity = Vec2::from_angle(rng.gen::<f32>() * TAU) * BOID_START_ACCEL * dt;
+
+            // Steer away from nearby obstacles
+            for obstacle in &self.obstacles {
+                let closest_point = obstacle.closest_point(boid.position);
+                let closest_point_to_boid = boid.position - closest_point;
+                let distance = closest_point_to_boid.length();
+                if distance <= boid.obstacle_avoidance_range {
+                    let away = closest_point_to_boid.normalize_or_zero();
+                    boid.velocity += away * boid.obstacle_avoidance_factor * dt;
                 }
-                // Boid velocity should not be the zero vector
-                if boid.velocity.length() < boid.min_speed { // Use or zero just in case precision
-                    boid.velocity = boid.velocity.normalize_or_zero() * boid.min_speed;
+            }
+
+            // Flee from predators within visual range, fleeing harder the closer they are
+            for predator in &self.predators {
+                let away = boid.position - predator.position;
+                let distance = away.length();
+                if distance <= boid.visual_range {
+                    let closeness = (boid.visual_range - distance) / boid.visual_range;
+                    boid.velocity += away.normalize_or_zero() * boid.flee_factor * closeness * dt;
                 }
             }
+
+            // Enforce min and max speed
+            // Give a random direction to velocity if speed is zero
+            if boid.velocity == Vec2::ZERO {
+                boid.velocity = Angle::random(&mut rng).to_vec() * BOID_START_ACCEL * dt;
+            }
+            // Boid velocity should not be the zero vector
+            if boid.velocity.length() < boid.min_speed { // Use or zero just in case precision
+                boid.velocity = boid.velocity.normalize_or_zero() * boid.min_speed;
+            }
             if boid.velocity.length() > boid.max_speed {
                 boid.velocity = boid.velocity.normalize() * boid.max_speed;
             }
@@ -154,7 +335,72 @@ impl EventHandler for Boids {
             back_to_bounds = back_to_bounds.normalize_or_zero() * boid.back_to_bounds_factor;
             boid.velocity += back_to_bounds * dt;
 
+            boid.previous_position = boid.position;
             boid.position += boid.velocity * dt;
+
+            // Project the boid out of any obstacle it has penetrated so it cannot pass through
+            for obstacle in &self.obstacles {
+                let half_extents = obstacle.half_extents;
+                let offset = self.boids[i].position - obstacle.center;
+                if offset.x.abs() < half_extents.x && offset.y.abs() < half_extents.y {
+                    let penetration = vec2(half_extents.x - offset.x.abs(), half_extents.y - offset.y.abs());
+                    if penetration.x < penetration.y {
+                        self.boids[i].position.x = obstacle.center.x + half_extents.x.copysign(offset.x);
+                    } else {
+                        self.boids[i].position.y = obstacle.center.y + half_extents.y.copysign(offset.y);
+                    }
+                }
+            }
+        }
+
+        // Predators seek the centroid of whatever boids are within their detection range
+        for predator in &mut self.predators {
+            let (nearby_position_sum, nearby_count) = positions.iter()
+                .filter(|position| predator.position.distance(**position) <= predator.detection_range)
+                .fold((Vec2::ZERO, 0), |(sum, count), position| (sum + *position, count + 1));
+            if nearby_count > 0 {
+                let centroid = nearby_position_sum / nearby_count as f32;
+                predator.velocity += (centroid - predator.position).normalize_or_zero() * predator.seek_factor * dt;
+            }
+            if predator.velocity.length() > predator.max_speed {
+                predator.velocity = predator.velocity.normalize() * predator.max_speed;
+            }
+
+            predator.previous_position = predator.position;
+            predator.position += predator.velocity * dt;
+        }
+    }
+
+    // Builds a one-off mesh overlaying each boid's visual/protected ranges, heading, and the
+    // links to the neighbours it currently counts within its visual range
+    fn build_debug_mesh(&self, context: &mut Context, positions: &[Vec2]) -> Option<Mesh> {
+        if self.boids.is_empty() {
+            return None;
+        }
+
+        let mut builder = MeshBuilder::new();
+        for (i, boid) in self.boids.iter().enumerate() {
+            let position = positions[i];
+            builder.circle(DrawMode::Stroke(StrokeOptions::default()), position, boid.visual_range, 0.5, DEBUG_VISUAL_RANGE_COLOR).unwrap();
+            builder.circle(DrawMode::Stroke(StrokeOptions::default()), position, boid.protected_range, 0.5, DEBUG_PROTECTED_RANGE_COLOR).unwrap();
+            builder.line(&[position, position + boid.velocity * DEBUG_VELOCITY_LINE_SCALE], 1.0, DEBUG_VELOCITY_COLOR).unwrap();
+            for (j, other_position) in positions.iter().enumerate() {
+                if i != j && (position - *other_position).length() <= boid.visual_range {
+                    builder.line(&[position, *other_position], 1.0, DEBUG_NEIGHBOUR_LINK_COLOR).unwrap();
+                }
+            }
+        }
+
+        Some(Mesh::from_data(context, builder.build()))
+    }
+}
+
+impl EventHandler for Boids {
+    fn update(&mut self, context: &mut Context) -> GameResult {
+        self.accumulator += context.time.delta().as_secs_f32();
+        while self.accumulator >= SIMULATION_DT {
+            self.accumulator -= SIMULATION_DT;
+            self.step(SIMULATION_DT);
         }
 
         Ok(())
@@ -162,15 +408,68 @@ impl EventHandler for Boids {
 
     fn draw(&mut self, context: &mut Context) -> GameResult {
         let mut canvas = graphics::Canvas::from_frame(context, Color::BLACK);
+        let alpha = self.accumulator / SIMULATION_DT;
+        let positions: Vec<Vec2> = self.boids.iter()
+            .map(|boid| boid.previous_position.lerp(boid.position, alpha))
+            .collect();
+
         self.boid_instance_array.clear();
-        for boid in self.boids.iter() {
+        for (boid, &position) in self.boids.iter().zip(&positions) {
             self.boid_instance_array.push(DrawParam::default()
-                .dest(boid.position)
-                .rotation(if boid.velocity != Vec2::ZERO { boid.velocity.y.atan2(boid.velocity.x) } else { 0.0 }) // No to_angle?
+                .dest(position)
+                .rotation(if boid.velocity != Vec2::ZERO { Angle::from_vec(boid.velocity).into() } else { 0.0 })
                 .scale(Vec2::splat(boid.scale))
             );
         }
         canvas.draw_instanced_mesh(self.boid_mesh.clone(), &self.boid_instance_array, DrawParam::default());
+        canvas.draw(&self.obstacle_mesh, DrawParam::default());
+
+        self.predator_instance_array.clear();
+        for predator in self.predators.iter() {
+            let position = predator.previous_position.lerp(predator.position, alpha);
+            self.predator_instance_array.push(DrawParam::default()
+                .dest(position)
+                .rotation(if predator.velocity != Vec2::ZERO { Angle::from_vec(predator.velocity).into() } else { 0.0 })
+                .scale(Vec2::splat(predator.scale))
+            );
+        }
+        canvas.draw_instanced_mesh(self.predator_mesh.clone(), &self.predator_instance_array, DrawParam::default());
+
+        if self.debug {
+            if let Some(debug_mesh) = self.build_debug_mesh(context, &positions) {
+                canvas.draw(&debug_mesh, DrawParam::default());
+            }
+        }
+
         canvas.finish(context)
     }
+
+    fn mouse_button_down_event(&mut self, _context: &mut Context, button: MouseButton, x: f32, y: f32) -> GameResult {
+        let cursor = vec2(x, y);
+        match button {
+            MouseButton::Left => {
+                let mut rng = rand::thread_rng();
+                self.boids.push(Self::new_boid(cursor, &mut rng));
+            }
+            MouseButton::Right => {
+                if let Some((nearest_index, _)) = self.boids.iter()
+                    .enumerate()
+                    .map(|(i, boid)| (i, boid.position.distance(cursor)))
+                    .filter(|(_, distance)| *distance <= BOID_DELETE_RADIUS)
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                {
+                    self.boids.remove(nearest_index);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, _context: &mut Context, input: KeyInput, repeated: bool) -> GameResult {
+        if !repeated && input.keycode == Some(KeyCode::D) {
+            self.debug = !self.debug;
+        }
+        Ok(())
+    }
 }